@@ -0,0 +1,56 @@
+//! Domain types shared by every service: what an item, a bid, and a
+//! bidder are, independent of where the bid came from (us or the auction
+//! house) or how it is persisted.
+
+/// Money, in the auction house's smallest currency unit
+pub type Amount = u64;
+
+pub type ItemId = String;
+pub type ItemIdRef<'a> = &'a str;
+
+/// The minimum amount by which a new bid must exceed the current one
+const MIN_BID_INCREMENT: Amount = 1;
+
+/// Identifies a bidder other than us, as reported by the auction house
+pub type BidderId = String;
+
+/// Who placed a bid
+///
+/// `Other` carries the auction house's own id for that bidder so we can
+/// tell different outside bidders apart -- e.g. to know whose bid a
+/// `BidCancelled` is retracting.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bidder {
+    /// Us
+    Sniper,
+    /// Any other bidder on the auction house
+    Other(BidderId),
+}
+
+/// A bid as it is known to have happened, i.e. already placed and visible
+/// on the auction house
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BidDetails {
+    pub bidder: Bidder,
+    pub price: Amount,
+}
+
+impl BidDetails {
+    /// Whether `price` would be a valid, strictly higher bid than this one
+    pub fn is_outbidded_by(&self, price: Amount) -> bool {
+        price >= self.next_valid_bid()
+    }
+
+    /// The smallest price that would outbid this one
+    pub fn next_valid_bid(&self) -> Amount {
+        self.price + MIN_BID_INCREMENT
+    }
+}
+
+/// A bid we want to place, before it has been submitted to the auction
+/// house
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ItemBid {
+    pub item: ItemId,
+    pub price: Amount,
+}
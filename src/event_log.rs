@@ -0,0 +1,75 @@
+//! The append-only log every service reads from and writes to
+//!
+//! Services never talk to each other directly: they read the events they
+//! care about off this log, and write the events they produce to it,
+//! inside the same transaction as whatever persistent state change
+//! produced them.
+
+use crate::auction::ItemId;
+use crate::persistence::{self, Connection};
+use crate::service::{auction_house, bidding_engine, ui};
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A position in the log
+///
+/// Monotonically increasing; a service's `ProgressTracker` entry is just
+/// the highest `Offset` it has fully processed.
+pub type Offset = u64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub id: Offset,
+    pub details: EventDetails,
+}
+
+/// An event produced by one of the services, as stored in the log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventDetails {
+    AuctionHouse(AuctionHouseEvent),
+    Ui(ui::Event),
+    BiddingEngine(bidding_engine::Event),
+    /// An internal heartbeat from the `timer` service, carrying no
+    /// payload of its own; services re-evaluate any time-dependent state
+    /// (e.g. deferred snipes) whenever they see one.
+    Tick,
+}
+
+/// An event coming from the auction house, scoped to the item it is about
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuctionHouseEvent {
+    pub item: ItemId,
+    pub event: auction_house::EventDetails,
+}
+
+/// A read-only view of the log
+pub trait EventReader {
+    /// Reads up to `limit` events after `after` (or from the start, if
+    /// `None`), blocking for up to `timeout` for new events to show up if
+    /// none are available yet
+    fn read(&self, after: Option<Offset>, limit: usize, timeout: Option<Duration>) -> Result<Vec<Event>>;
+
+    /// The `Offset` of the most recent event in the log, if any
+    ///
+    /// Used to report a service's replication lag: how far its stored
+    /// progress is behind the log it is following.
+    fn latest_offset(&self) -> Result<Option<Offset>>;
+}
+
+pub type SharedReader = Arc<dyn EventReader + Send + Sync>;
+
+/// A way to append events, transactionally alongside whatever produced
+/// them
+pub trait EventWriter<P>
+where
+    P: persistence::Persistence,
+{
+    fn write_tr<'a>(
+        &self,
+        conn: &mut <P::Connection as Connection>::Transaction<'a>,
+        events: &[EventDetails],
+    ) -> Result<()>;
+}
+
+pub type SharedWriter<P> = Arc<dyn EventWriter<P> + Send + Sync>;
@@ -0,0 +1,196 @@
+//! Lightweight, always-on instrumentation for the service loops
+//!
+//! Every `spawn_event_loop` measures how long each `f(event.details)` call
+//! takes, how many events it has processed, and how far its stored
+//! progress `Offset` is behind the latest one in the log. None of this is
+//! sampled: it needs to be cheap enough to leave on unconditionally, so
+//! an operator always has per-`ServiceId` latency, throughput, and
+//! replication lag to look at.
+
+use crate::service::{ServiceId, ServiceIdRef};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Metrics {
+    /// How long a single `f(event.details)` call took, success or not
+    fn record_duration(&self, service_id: ServiceIdRef, elapsed: Duration);
+
+    /// One more event was handled by `f` without error
+    fn record_event_processed(&self, service_id: ServiceIdRef);
+
+    /// How many events behind the latest log `Offset` this service's
+    /// stored progress currently is
+    fn record_lag(&self, service_id: ServiceIdRef, lag: u64);
+
+    /// The queryable [`Counters`] this implementation keeps for
+    /// `service_id`, if any
+    ///
+    /// `LoggingMetrics` only ever writes log lines and keeps none;
+    /// [`CountingMetrics`] is the implementation an operator actually
+    /// reads latency, throughput, and replication lag back out of.
+    fn counters(&self, _service_id: ServiceIdRef) -> Option<Arc<Counters>> {
+        None
+    }
+}
+
+pub type SharedMetrics = Arc<dyn Metrics + Send + Sync>;
+
+/// Logs everything; the default every service gets for free
+pub struct LoggingMetrics;
+
+impl LoggingMetrics {
+    pub fn new_shared() -> SharedMetrics {
+        Arc::new(Self)
+    }
+}
+
+impl Metrics for LoggingMetrics {
+    fn record_duration(&self, service_id: ServiceIdRef, elapsed: Duration) {
+        log::debug!("{}: processed event in {:?}", service_id, elapsed);
+    }
+
+    fn record_event_processed(&self, service_id: ServiceIdRef) {
+        log::trace!("{}: event processed", service_id);
+    }
+
+    fn record_lag(&self, service_id: ServiceIdRef, lag: u64) {
+        log::debug!("{}: {} events behind", service_id, lag);
+    }
+}
+
+/// An RAII scope that reports its own elapsed wall-clock time to
+/// `Metrics` when dropped
+///
+/// Because it reports on `Drop`, timing is recorded whether the scope
+/// exits normally or via `?` on an `Err`.
+pub struct TraceTime {
+    start: Instant,
+    service_id: String,
+    metrics: SharedMetrics,
+}
+
+impl TraceTime {
+    pub fn start(service_id: ServiceIdRef, metrics: SharedMetrics) -> Self {
+        Self {
+            start: Instant::now(),
+            service_id: service_id.to_owned(),
+            metrics,
+        }
+    }
+}
+
+impl Drop for TraceTime {
+    fn drop(&mut self) {
+        self.metrics.record_duration(&self.service_id, self.start.elapsed());
+    }
+}
+
+/// Starts a [`TraceTime`] scope for the rest of the current block
+///
+/// ```ignore
+/// trace_time!(metrics, service_id);
+/// f(event.details)?;
+/// ```
+macro_rules! trace_time {
+    ($metrics:expr, $service_id:expr) => {
+        let _trace_time = $crate::metrics::TraceTime::start($service_id, $metrics.clone());
+    };
+}
+
+pub(crate) use trace_time;
+
+/// A plain `events processed` / `last duration` / `replication lag`
+/// snapshot for a single service, kept by [`CountingMetrics`] so an
+/// operator can read it back out (e.g. over an admin endpoint) instead of
+/// scraping `LoggingMetrics`'s log lines
+#[derive(Default)]
+pub struct Counters {
+    events_processed: AtomicU64,
+    last_duration_nanos: AtomicU64,
+    lag: AtomicU64,
+}
+
+impl Counters {
+    fn record_duration(&self, elapsed: Duration) {
+        self.last_duration_nanos
+            .store(elapsed.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn record_event_processed(&self) {
+        self.events_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_lag(&self, lag: u64) {
+        self.lag.store(lag, Ordering::Relaxed);
+    }
+
+    /// How long the most recently processed event took to handle
+    pub fn last_duration(&self) -> Duration {
+        Duration::from_nanos(self.last_duration_nanos.load(Ordering::Relaxed))
+    }
+
+    /// How many events this service has processed in total
+    pub fn events_processed(&self) -> u64 {
+        self.events_processed.load(Ordering::Relaxed)
+    }
+
+    /// How many events behind the latest log `Offset` this service's
+    /// stored progress was, as of the last read
+    pub fn lag(&self) -> u64 {
+        self.lag.load(Ordering::Relaxed)
+    }
+}
+
+/// Keeps a queryable [`Counters`] per `ServiceId`, alongside whatever
+/// other `Metrics` implementation (typically [`LoggingMetrics`]) it wraps
+///
+/// This is what actually answers the module doc's promise of an operator
+/// being able to look at per-`ServiceId` latency, throughput, and
+/// replication lag: `record_*` updates both the wrapped implementation
+/// and this service's `Counters`, and `counters` hands back a live handle
+/// to read them from.
+pub struct CountingMetrics {
+    inner: SharedMetrics,
+    counters: Mutex<BTreeMap<ServiceId, Arc<Counters>>>,
+}
+
+impl CountingMetrics {
+    pub fn new_shared(inner: SharedMetrics) -> SharedMetrics {
+        Arc::new(Self {
+            inner,
+            counters: Mutex::new(BTreeMap::default()),
+        })
+    }
+
+    fn counters_for(&self, service_id: ServiceIdRef) -> Arc<Counters> {
+        self.counters
+            .lock()
+            .expect("lock")
+            .entry(service_id.to_owned())
+            .or_insert_with(|| Arc::new(Counters::default()))
+            .clone()
+    }
+}
+
+impl Metrics for CountingMetrics {
+    fn record_duration(&self, service_id: ServiceIdRef, elapsed: Duration) {
+        self.inner.record_duration(service_id, elapsed);
+        self.counters_for(service_id).record_duration(elapsed);
+    }
+
+    fn record_event_processed(&self, service_id: ServiceIdRef) {
+        self.inner.record_event_processed(service_id);
+        self.counters_for(service_id).record_event_processed();
+    }
+
+    fn record_lag(&self, service_id: ServiceIdRef, lag: u64) {
+        self.inner.record_lag(service_id, lag);
+        self.counters_for(service_id).record_lag(lag);
+    }
+
+    fn counters(&self, service_id: ServiceIdRef) -> Option<Arc<Counters>> {
+        Some(self.counters_for(service_id))
+    }
+}
@@ -0,0 +1,129 @@
+pub mod postgres;
+mod in_memory;
+
+pub use self::in_memory::*;
+
+use anyhow::Result;
+use std::time::Duration;
+
+/// A backend capable of handing out connections to the durable store
+///
+/// There are two implementations: a real one, backed by Postgres, and an
+/// in-memory one used in tests and for local development.
+pub trait Persistence {
+    type Connection: Connection;
+}
+
+/// A single logical connection, capable of starting transactions
+pub trait Connection {
+    type Transaction<'a>: Transaction
+    where
+        Self: 'a;
+
+    fn start_transaction(&mut self) -> Result<Self::Transaction<'_>>;
+}
+
+/// An open transaction against the store
+///
+/// Every unit of work in this codebase (loading state, writing events,
+/// advancing progress) happens inside one of these, so that it either
+/// all lands or none of it does.
+pub trait Transaction {
+    fn commit(self) -> Result<()>;
+    fn rollback(self) -> Result<()>;
+}
+
+/// The number of times [`with_serialization_retry`] will re-run `f` before
+/// giving up and propagating the error as fatal.
+const MAX_RETRY_ATTEMPTS: u32 = 5;
+
+/// The backoff before the first retry; doubled on every subsequent one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Runs `f`, retrying it from scratch when it fails because of a Postgres
+/// serialization failure (`SqlState` `40001`)
+///
+/// Under `SERIALIZABLE` isolation two transactions touching the same row
+/// can legitimately conflict; the correct response is to roll back and
+/// re-run the whole unit of work -- including the initial state load --
+/// rather than treat it as a fatal error. `f` is expected to open its own
+/// transaction and commit (or roll back) before returning; on retry we
+/// call it again from the top so it re-reads state against a fresh
+/// snapshot.
+///
+/// Any other error, or running out of attempts, is propagated as-is.
+/// Backends that never produce this error (e.g. [`InMemoryPersistence`])
+/// make this a no-op: `f` just runs once.
+pub fn with_serialization_retry<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 0.. {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < MAX_RETRY_ATTEMPTS && is_serialization_failure(&e) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Walks `err`'s source chain looking for a Postgres `DbError` whose
+/// `SqlState` is the serialization-failure code (`40001`)
+fn is_serialization_failure(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<postgres::Error>())
+        .filter_map(|e| e.as_db_error())
+        .any(|db_err| is_serialization_failure_code(db_err.code()))
+}
+
+/// Whether `code` is the SQLSTATE Postgres reports for a `SERIALIZABLE`
+/// isolation conflict, as opposed to any other kind of database error
+///
+/// Split out from [`is_serialization_failure`] so the "what counts as
+/// retryable" check can be exercised without needing a real `DbError`,
+/// which `postgres` only ever constructs from an actual wire response.
+fn is_serialization_failure_code(code: &postgres::error::SqlState) -> bool {
+    *code == postgres::error::SqlState::T_R_SERIALIZATION_FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn only_the_serialization_failure_sqlstate_is_retryable() {
+        assert!(is_serialization_failure_code(
+            &postgres::error::SqlState::T_R_SERIALIZATION_FAILURE
+        ));
+        assert!(!is_serialization_failure_code(&postgres::error::SqlState::UNIQUE_VIOLATION));
+    }
+
+    #[test]
+    fn with_serialization_retry_returns_the_first_success_without_retrying() {
+        let calls = Cell::new(0);
+
+        let result = with_serialization_retry(|| {
+            calls.set(calls.get() + 1);
+            Ok::<_, anyhow::Error>(42)
+        });
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn with_serialization_retry_propagates_non_serialization_errors_immediately() {
+        let calls = Cell::new(0);
+
+        let result = with_serialization_retry(|| {
+            calls.set(calls.get() + 1);
+            Err::<(), _>(anyhow::anyhow!("not a serialization failure"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+use super::*;
+
+pub struct InMemoryPersistence;
+
+impl Persistence for InMemoryPersistence {
+    type Connection = InMemoryConnection;
+}
+
+pub struct InMemoryConnection;
+
+impl Connection for InMemoryConnection {
+    type Transaction<'a> = InMemoryTransaction;
+
+    fn start_transaction(&mut self) -> Result<Self::Transaction<'_>> {
+        Ok(InMemoryTransaction)
+    }
+}
+
+/// There is nothing to actually commit or roll back in memory, and -- not
+/// talking to Postgres -- there is no `SERIALIZABLE` isolation to conflict
+/// under, so [`super::with_serialization_retry`] is a no-op for this
+/// backend.
+pub struct InMemoryTransaction;
+
+impl Transaction for InMemoryTransaction {
+    fn commit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn rollback(self) -> Result<()> {
+        Ok(())
+    }
+}
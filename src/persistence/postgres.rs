@@ -0,0 +1,45 @@
+use super::*;
+
+pub struct PostgresPersistence;
+
+impl Persistence for PostgresPersistence {
+    type Connection = PostgresConnection;
+}
+
+pub struct PostgresConnection {
+    client: postgres::Client,
+}
+
+impl Connection for PostgresConnection {
+    type Transaction<'a> = PostgresTransaction<'a>;
+
+    fn start_transaction(&mut self) -> Result<Self::Transaction<'_>> {
+        Ok(PostgresTransaction(self.client.transaction()?))
+    }
+}
+
+pub struct PostgresTransaction<'a>(postgres::Transaction<'a>);
+
+impl<'a> Transaction for PostgresTransaction<'a> {
+    fn commit(self) -> Result<()> {
+        Ok(self.0.commit()?)
+    }
+
+    fn rollback(self) -> Result<()> {
+        Ok(self.0.rollback()?)
+    }
+}
+
+impl<'a> std::ops::Deref for PostgresTransaction<'a> {
+    type Target = postgres::Transaction<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a> std::ops::DerefMut for PostgresTransaction<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
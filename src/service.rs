@@ -1,6 +1,9 @@
 pub mod auction;
-pub mod progress;
+pub mod auction_house;
 pub mod bidding_engine;
+pub mod dispatch;
+pub mod progress;
+pub mod timer;
 
 use anyhow::format_err;
 use anyhow::Result;
@@ -11,6 +14,8 @@ use std::sync::{
 use std::thread;
 
 use crate::event_log;
+use crate::metrics::{self, SharedMetrics};
+use crate::persistence;
 
 use self::progress::SharedProgressTracker;
 
@@ -24,9 +29,24 @@ pub type ServiceIdRef<'a> = &'a str;
 /// of them by stopping everything.
 pub struct ServiceControl {
     stop: Arc<AtomicBool>,
+    metrics: SharedMetrics,
 }
 
 impl ServiceControl {
+    pub fn new(metrics: SharedMetrics) -> Self {
+        Self {
+            stop: Arc::new(AtomicBool::new(false)),
+            metrics,
+        }
+    }
+
+    /// The metrics sink every `spawn_event_loop` on this control reports
+    /// to, so an operator (or a test) can read per-`ServiceId` timing,
+    /// throughput, and replication lag back out
+    pub fn metrics(&self) -> &SharedMetrics {
+        &self.metrics
+    }
+
     /// Start a new service as a loop, with a certain body
     ///
     /// This will take care of checking termination condition and
@@ -60,6 +80,56 @@ impl ServiceControl {
         F: FnMut(event_log::EventDetails) -> Result<()> + Send + Sync + 'static,
     {
         let service_id = service_id.to_owned();
+        let metrics = self.metrics.clone();
+
+        let mut progress = match progress_store.load(&service_id) {
+            Err(e) => return JoinHandle::new(thread::spawn(move || Err(e))),
+            Ok(o) => o,
+        };
+
+        self.spawn_loop(move || {
+            for event in event_reader
+                .read(progress.clone(), 1, Some(std::time::Duration::from_secs(1)))?
+                .drain(..)
+            {
+                {
+                    metrics::trace_time!(metrics, &service_id);
+                    f(event.details)?;
+                }
+                metrics.record_event_processed(&service_id);
+
+                progress = Some(event.id.clone());
+                progress_store.store(&service_id, &event.id)?;
+
+                report_lag(&*event_reader, &metrics, &service_id, &progress)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Like [`Self::spawn_event_loop`], but tolerant of transient Postgres
+    /// serialization failures
+    ///
+    /// `f` is expected to run its work (load, store, write) inside a single
+    /// `persistence::Transaction`, as e.g. `BiddingEngine::handle_event_with`
+    /// does. Under `SERIALIZABLE` isolation, two services touching the same
+    /// row can legitimately get a `40001` conflict; that is not a crash, it
+    /// is a signal to roll back and re-apply the event against fresh state.
+    /// This wraps each `f(event.details)` call in
+    /// [`persistence::with_serialization_retry`] so only genuinely fatal
+    /// errors reach [`Self::spawn_loop`] and stop the service.
+    pub fn spawn_event_loop_retrying<F>(
+        &self,
+        progress_store: SharedProgressTracker,
+        service_id: ServiceIdRef,
+        event_reader: event_log::SharedReader,
+        mut f: F,
+    ) -> JoinHandle
+    where
+        F: FnMut(event_log::EventDetails) -> Result<()> + Send + Sync + 'static,
+    {
+        let service_id = service_id.to_owned();
+        let metrics = self.metrics.clone();
 
         let mut progress = match progress_store.load(&service_id) {
             Err(e) => return JoinHandle::new(thread::spawn(move || Err(e))),
@@ -71,16 +141,37 @@ impl ServiceControl {
                 .read(progress.clone(), 1, Some(std::time::Duration::from_secs(1)))?
                 .drain(..)
             {
-                f(event.details)?;
+                {
+                    metrics::trace_time!(metrics, &service_id);
+                    persistence::with_serialization_retry(|| f(event.details.clone()))?;
+                }
+                metrics.record_event_processed(&service_id);
 
                 progress = Some(event.id.clone());
                 progress_store.store(&service_id, &event.id)?;
+
+                report_lag(&*event_reader, &metrics, &service_id, &progress)?;
             }
             Ok(())
         })
     }
 }
 
+/// Reports how many events `service_id` is behind the latest one in
+/// `event_reader`'s log
+fn report_lag(
+    event_reader: &dyn event_log::EventReader,
+    metrics: &SharedMetrics,
+    service_id: ServiceIdRef,
+    progress: &Option<event_log::Offset>,
+) -> Result<()> {
+    if let Some(latest) = event_reader.latest_offset()? {
+        let lag = latest.saturating_sub(progress.unwrap_or(0));
+        metrics.record_lag(service_id, lag);
+    }
+    Ok(())
+}
+
 /// Simple thread join wrapper that joins the thread on drop
 ///
 /// TODO: Would it be better to have it set the `stop` flag toc terminate all threads
@@ -0,0 +1,30 @@
+//! Events published by the external auction house
+//!
+//! This is the one-way feed of what's actually happening on the listing;
+//! everything the `bidding_engine` knows about an auction ultimately comes
+//! from replaying these.
+
+use crate::auction::BidDetails;
+use std::time::SystemTime;
+
+/// Mirrors the cancel_bid/claim_bid/end_auction instruction set of a full
+/// auction program: a bid can be retracted before the auction closes, and
+/// once it does the winner still has to claim the item.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventDetails {
+    /// A bid was placed, by us or anyone else
+    Bid(BidDetails),
+    /// A previously placed bid was retracted
+    BidCancelled(BidDetails),
+    /// The listing's deadline was (re)announced
+    ///
+    /// Lets `AuctionState` learn `ends_at` without us having to poll the
+    /// auction house for it.
+    EndsAt(SystemTime),
+    /// The listing closed
+    Closed,
+    /// We won the auction and must now settle it
+    Won,
+    /// The winner claimed the item, settling the auction
+    Claim,
+}
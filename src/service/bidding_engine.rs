@@ -12,6 +12,7 @@ use anyhow::Result;
 use std::{
     collections::BTreeMap,
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
 use thiserror::Error;
 
@@ -49,15 +50,44 @@ pub trait BiddingStateStore {
     ) -> Result<()> {
         self.store_tr(&mut conn.start_transaction()?, item_id, state)
     }
+
+    /// Lists every item we are still bidding on (i.e. not yet `closed`)
+    ///
+    /// Used by the `Tick` handler to re-evaluate every pending auction, so
+    /// a snipe that was deferred until its window opens is actually
+    /// placed once enough real time has passed.
+    fn list_pending_tr<'a>(
+        &self,
+        conn: &mut <<<Self as BiddingStateStore>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+    ) -> Result<Vec<ItemId>>;
+
+    /// Atomically allocates the next value of a single, global,
+    /// monotonically increasing counter
+    ///
+    /// Used to stamp every `PrioritizedBid` with an `insertion_id` at the
+    /// moment we decide to bid, so the dispatch queue can break ties
+    /// between equally urgent bids by submission order. Living here
+    /// rather than in `dispatch` means the counter survives a restart the
+    /// same way the rest of `AuctionBiddingState` does.
+    fn next_insertion_id_tr<'a>(
+        &self,
+        conn: &mut <<<Self as BiddingStateStore>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+    ) -> Result<u64>;
 }
 
 pub type SharedBiddingStateStore<P> = Arc<dyn BiddingStateStore<Persistence = P> + Send + Sync>;
 
-pub struct InMemoryBiddingStateStore(Mutex<BTreeMap<ItemId, AuctionBiddingState>>);
+pub struct InMemoryBiddingStateStore {
+    states: Mutex<BTreeMap<ItemId, AuctionBiddingState>>,
+    next_insertion_id: Mutex<u64>,
+}
 
 impl InMemoryBiddingStateStore {
     pub fn new() -> Self {
-        Self(Mutex::new(BTreeMap::default()))
+        Self {
+            states: Mutex::new(BTreeMap::default()),
+            next_insertion_id: Mutex::new(0),
+        }
     }
 
     pub fn new_shared() -> SharedBiddingStateStore<persistence::InMemoryPersistence> {
@@ -73,7 +103,7 @@ impl BiddingStateStore for InMemoryBiddingStateStore {
         _conn: &mut persistence::InMemoryTransaction,
         item_id: ItemIdRef,
     ) -> Result<Option<AuctionBiddingState>> {
-        Ok(self.0.lock().expect("lock").get(item_id).cloned())
+        Ok(self.states.lock().expect("lock").get(item_id).cloned())
     }
 
     fn store_tr<'a>(
@@ -82,12 +112,30 @@ impl BiddingStateStore for InMemoryBiddingStateStore {
         item_id: ItemIdRef,
         state: AuctionBiddingState,
     ) -> Result<()> {
-        self.0
+        self.states
             .lock()
             .expect("lock")
             .insert(item_id.to_owned(), state);
         Ok(())
     }
+
+    fn list_pending_tr<'a>(&self, _conn: &mut persistence::InMemoryTransaction) -> Result<Vec<ItemId>> {
+        Ok(self
+            .states
+            .lock()
+            .expect("lock")
+            .iter()
+            .filter(|(_, state)| !state.state.closed)
+            .map(|(item_id, _)| item_id.clone())
+            .collect())
+    }
+
+    fn next_insertion_id_tr<'a>(&self, _conn: &mut persistence::InMemoryTransaction) -> Result<u64> {
+        let mut next_insertion_id = self.next_insertion_id.lock().expect("lock");
+        let id = *next_insertion_id;
+        *next_insertion_id += 1;
+        Ok(id)
+    }
 }
 
 #[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
@@ -104,40 +152,143 @@ pub enum AuctionError {
     UnknownAuction(ItemId),
 }
 
+/// A bid decision, carrying everything the dispatch queue needs to
+/// prioritize and, if necessary, evict it without going back to
+/// `AuctionBiddingState`
+///
+/// Serializable so `dispatch::postgres` can store it as the
+/// `dispatch_job.payload` JSONB column.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PrioritizedBid {
+    pub bid: ItemBid,
+    /// The auction's close time, if known, at the moment we decided to
+    /// bid -- used to order the dispatch queue so urgent bids aren't
+    /// stuck behind ones with hours to spare
+    pub ends_at: Option<SystemTime>,
+    /// This bid's position in the global, monotonically increasing
+    /// insertion order, allocated by [`BiddingStateStore::next_insertion_id_tr`]
+    ///
+    /// Breaks ties between bids whose auctions close at the same time (or
+    /// whose close time isn't known at all), so equally urgent bids are
+    /// still processed in the order we decided to place them.
+    pub insertion_id: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Event {
     /// We are placing a bid
-    Bid(ItemBid),
+    Bid(PrioritizedBid),
     /// Auction house event caused an error
     AuctionError(AuctionError),
     /// User event caused an error
     UserError(UserError),
+    /// We won and must now settle the auction
+    ClaimRequired(ItemId),
+    /// A previously queued bid was dropped without being submitted,
+    /// because its auction closed (or was about to) before the dispatch
+    /// queue got to it
+    BidEvicted(ItemId),
 }
 
-#[derive(Default, Copy, Clone, PartialEq, Eq, Debug)]
+/// We only need to remember a handful of the top bidders to safely
+/// recompute the leader after a `BidCancelled`; this bounds how many we
+/// track so a chatty auction can't grow the state without limit.
+const MAX_TRACKED_BIDS: usize = 8;
+
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
 pub struct AuctionState {
-    pub higest_bid: Option<BidDetails>,
+    /// The top outstanding bid we know about per bidder
+    ///
+    /// Keeping more than just the current leader is what lets us
+    /// recompute who is actually winning after a `BidCancelled` retracts
+    /// a bid, without having to replay the whole history.
+    pub bids: BTreeMap<Bidder, Amount>,
     pub closed: bool,
+    /// When the listing closes, once known
+    ///
+    /// Populated from `auction_house::EventDetails::EndsAt`. Until we've
+    /// seen that event, `get_next_bid` has no choice but to bid as soon as
+    /// outbid, same as before this module learned about deadlines.
+    pub ends_at: Option<SystemTime>,
+    /// The price of the bid we last decided to place, if the auction
+    /// house hasn't echoed it back (or made it moot) yet
+    ///
+    /// Without this, a `Tick` landing while our bid is still sitting in
+    /// the dispatch queue (or in flight to the auction house) would see
+    /// the exact same "we're outbid" situation and queue a duplicate
+    /// `Event::Bid` for the same price. Cleared implicitly: once the
+    /// situation actually changes, `get_next_bid` computes a different
+    /// target and bids again regardless of this field. Reset explicitly
+    /// only when the auction house cancels that very bid of ours.
+    pub pending_bid: Option<Amount>,
 }
 
 impl AuctionState {
+    /// The current leading bid, recomputed from `bids` rather than cached,
+    /// so it stays correct after a cancellation
+    pub fn higest_bid(&self) -> Option<BidDetails> {
+        self.bids
+            .iter()
+            .max_by_key(|(_, &price)| price)
+            .map(|(bidder, &price)| BidDetails {
+                bidder: bidder.clone(),
+                price,
+            })
+    }
+
+    fn evict_lowest_if_over_capacity(&mut self) {
+        while self.bids.len() > MAX_TRACKED_BIDS {
+            let lowest = self
+                .bids
+                .iter()
+                .min_by_key(|(_, &price)| price)
+                .map(|(bidder, _)| bidder.clone());
+            match lowest {
+                Some(bidder) => {
+                    self.bids.remove(&bidder);
+                }
+                None => break,
+            }
+        }
+    }
+
     pub fn handle_auction_event(mut self, event: auction_house::EventDetails) -> Self {
         match event {
+            // Recorded unconditionally, not just when it beats the
+            // current leader: a standing bid below the leader's is still
+            // live, and if the leader's bid is later cancelled we need it
+            // in `bids` to recompute who's actually winning now.
             auction_house::EventDetails::Bid(bid) => {
-                if !self.closed
-                    && self
-                        .higest_bid
-                        .map(|highest| highest.is_outbidded_by(bid.price))
-                        .unwrap_or(true)
-                {
-                    self.higest_bid = Some(bid);
+                if !self.closed {
+                    self.bids.insert(bid.bidder, bid.price);
+                    self.evict_lowest_if_over_capacity();
+                }
+                self
+            }
+            // Our own bid being cancelled takes the same path as anyone
+            // else's: the bidder's entry is dropped, the leader is
+            // recomputed, and if that reopens room under `max_bid` the
+            // caller will see `get_next_bid` return a fresh price.
+            auction_house::EventDetails::BidCancelled(bid) => {
+                self.bids.remove(&bid.bidder);
+                // The cancelled bid is the one `pending_bid` remembers, so
+                // re-placing it at that price is not a duplicate.
+                if bid.bidder == Bidder::Sniper && self.pending_bid == Some(bid.price) {
+                    self.pending_bid = None;
                 }
                 self
             }
+            auction_house::EventDetails::EndsAt(ends_at) => {
+                self.ends_at = Some(ends_at);
+                self
+            }
             auction_house::EventDetails::Closed => {
                 self.closed = true;
                 self
             }
+            // Handled at the `AuctionBiddingState` level, where we have
+            // the `item_id` needed to emit `Event::ClaimRequired`.
+            auction_house::EventDetails::Won | auction_house::EventDetails::Claim => self,
         }
     }
 
@@ -174,12 +325,25 @@ impl AuctionState {
     }
     */
 
-    fn get_next_bid(self, max_price: Amount) -> Option<Amount> {
-        if self.closed {
+    /// Whether `now` is already inside the snipe window, i.e. close
+    /// enough to `ends_at` that we should actually place a bid rather
+    /// than keep waiting
+    ///
+    /// Returns `true` if `ends_at` isn't known yet, so behavior is
+    /// unchanged for auctions we haven't seen a deadline for.
+    fn in_snipe_window(&self, now: SystemTime, snipe_lead: Duration) -> bool {
+        match self.ends_at {
+            Some(ends_at) => now + snipe_lead >= ends_at,
+            None => true,
+        }
+    }
+
+    fn get_next_bid(&self, max_price: Amount, now: SystemTime, snipe_lead: Duration) -> Option<Amount> {
+        if self.closed || !self.in_snipe_window(now, snipe_lead) {
             return None;
         }
 
-        match self.higest_bid {
+        let target = match self.higest_bid() {
             // TODO: is 0 a valid bid? :)
             None => Some(0),
 
@@ -196,28 +360,50 @@ impl AuctionState {
                     None
                 }
             }
+        }?;
+
+        // We've already asked to place this exact bid and are waiting to
+        // hear back; don't ask again until something actually changes.
+        if self.pending_bid == Some(target) {
+            None
+        } else {
+            Some(target)
         }
     }
 }
 
-#[derive(Copy, Clone, Default, PartialEq, Debug)]
+/// How long before an auction's close we start actually placing bids
+///
+/// Before this, we still track the running `higest_bid` so we know where
+/// we stand, we just don't materialize an `Event::Bid` yet.
+pub const DEFAULT_SNIPE_LEAD: Duration = Duration::from_secs(5);
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct AuctionBiddingState {
     pub max_bid: Amount,
+    pub snipe_lead: Duration,
     pub state: AuctionState,
 }
 
+impl Default for AuctionBiddingState {
+    fn default() -> Self {
+        Self {
+            max_bid: Amount::default(),
+            snipe_lead: DEFAULT_SNIPE_LEAD,
+            state: AuctionState::default(),
+        }
+    }
+}
+
 impl AuctionBiddingState {
     pub fn handle_auction_house_event(self, event: auction_house::EventDetails) -> Self {
         Self {
-            max_bid: self.max_bid,
             state: self.state.handle_auction_event(event),
+            ..self
         }
     }
     pub fn handle_new_max_bid(self, max_bid: Amount) -> Self {
-        Self {
-            max_bid: max_bid,
-            ..self
-        }
+        Self { max_bid, ..self }
     }
 }
 
@@ -268,29 +454,77 @@ impl<P> BiddingEngine<P> {
         Ok(())
     }
 
+    /// Builds the `Event::Bid`, if any, we should emit for `item_id` given
+    /// `state` as it stands right now, returning the state to persist
+    /// alongside it
+    ///
+    /// When a bid is decided, the returned state records it as
+    /// `pending_bid`, so a `Tick` landing before the auction house echoes
+    /// it back doesn't queue the same bid again.
+    ///
+    /// Shared by every place that can cause us to place a bid: a new
+    /// auction house event, a new max bid, and a `Tick` re-evaluating a
+    /// previously deferred snipe.
+    fn next_bid_event(
+        item_id: ItemId,
+        state: AuctionBiddingState,
+        now: SystemTime,
+        insertion_id: u64,
+    ) -> (AuctionBiddingState, Option<Event>) {
+        match state.state.get_next_bid(state.max_bid, now, state.snipe_lead) {
+            Some(our_bid) => {
+                let event = Event::Bid(PrioritizedBid {
+                    bid: ItemBid {
+                        item: item_id,
+                        price: our_bid,
+                    },
+                    ends_at: state.state.ends_at,
+                    insertion_id,
+                });
+                let state = AuctionBiddingState {
+                    state: AuctionState {
+                        pending_bid: Some(our_bid),
+                        ..state.state.clone()
+                    },
+                    ..state
+                };
+                (state, Some(event))
+            }
+            None => (state, None),
+        }
+    }
+
     pub fn handle_auction_house_event(
         item_id: ItemId,
         old_state: Option<AuctionBiddingState>,
         event: crate::service::auction_house::EventDetails,
+        now: SystemTime,
+        insertion_id: u64,
     ) -> Result<(Option<AuctionBiddingState>, Vec<Event>)> {
         Ok(if let Some(auction_state) = old_state {
-            let new_state = auction_state.handle_auction_house_event(event);
+            // Winning doesn't change anything about the bid history, it
+            // just means settlement is now our job -- but it does mean
+            // this auction is no longer pending: it has to flip `closed`
+            // here too (rather than waiting on a separate `Closed` event
+            // that may arrive late or not at all), or `list_pending_tr`
+            // keeps handing it to every `Tick` and we keep bidding on an
+            // auction we've already won.
+            if matches!(event, auction_house::EventDetails::Won) {
+                let new_state = AuctionBiddingState {
+                    state: AuctionState {
+                        closed: true,
+                        ..auction_state.state
+                    },
+                    ..auction_state
+                };
+                return Ok((Some(new_state), vec![Event::ClaimRequired(item_id)]));
+            }
+
+            let new_state = auction_state.clone().handle_auction_house_event(event);
 
             if new_state != auction_state {
-                (
-                    Some(new_state),
-                    new_state
-                        .state
-                        .get_next_bid(new_state.max_bid)
-                        .map(move |our_bid| {
-                            Event::Bid(ItemBid {
-                                item: item_id,
-                                price: our_bid,
-                            })
-                        })
-                        .into_iter()
-                        .collect(),
-                )
+                let (new_state, event) = Self::next_bid_event(item_id, new_state, now, insertion_id);
+                (Some(new_state), event.into_iter().collect())
             } else {
                 (None, vec![])
             }
@@ -306,38 +540,57 @@ impl<P> BiddingEngine<P> {
         item_id: ItemId,
         old_state: Option<AuctionBiddingState>,
         price: Amount,
+        now: SystemTime,
+        insertion_id: u64,
     ) -> Result<(Option<AuctionBiddingState>, Vec<Event>)> {
         let auction_state = old_state.unwrap_or_else(Default::default);
 
-        let new_state = auction_state.handle_new_max_bid(price);
+        let new_state = auction_state.clone().handle_new_max_bid(price);
 
         Ok(
             if new_state != auction_state
                 && new_state
                     .state
-                    .higest_bid
+                    .higest_bid()
                     .map(|bid| bid.bidder != Bidder::Sniper)
                     .unwrap_or(true)
             {
-                (
-                    Some(new_state),
-                    new_state
-                        .state
-                        .get_next_bid(new_state.max_bid)
-                        .map(move |our_bid| {
-                            Event::Bid(ItemBid {
-                                item: item_id,
-                                price: our_bid,
-                            })
-                        })
-                        .into_iter()
-                        .collect(),
-                )
+                let (new_state, event) = Self::next_bid_event(item_id, new_state, now, insertion_id);
+                (Some(new_state), event.into_iter().collect())
             } else {
                 (None, vec![])
             },
         )
     }
+
+    /// Re-evaluates a single pending auction on a `Tick`, placing the bid
+    /// if its snipe window has now opened
+    ///
+    /// Unlike the other handlers, it's purely the passage of time -- not
+    /// some new fact about the auction -- that might now satisfy
+    /// `get_next_bid`'s window check. `AuctionBiddingState` only changes
+    /// here when that happens, and only to record the bid as
+    /// `pending_bid` so the next `Tick` doesn't place it again before the
+    /// auction house has acknowledged it.
+    pub fn handle_tick_event(
+        item_id: ItemId,
+        old_state: Option<AuctionBiddingState>,
+        now: SystemTime,
+        insertion_id: u64,
+    ) -> Result<(Option<AuctionBiddingState>, Vec<Event>)> {
+        Ok(match old_state {
+            Some(state) => {
+                let before = state.clone();
+                let (new_state, event) = Self::next_bid_event(item_id, state, now, insertion_id);
+                if new_state != before {
+                    (Some(new_state), event.into_iter().collect())
+                } else {
+                    (None, vec![])
+                }
+            }
+            None => (None, vec![]),
+        })
+    }
 }
 
 impl<P> service::LogFollowerService<P> for BiddingEngine<P>
@@ -349,21 +602,44 @@ where
         transaction: &mut <<P as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
         event: event_log::EventDetails,
     ) -> Result<()> {
+        let now = SystemTime::now();
         Ok(match event {
-            event_log::EventDetails::AuctionHouse(event) => Self::handle_event_with(
-                transaction,
-                &self.bidding_state_store,
-                &self.even_writer,
-                event.item.clone(),
-                |old_state| Self::handle_auction_house_event(event.item, old_state, event.event),
-            )?,
-            event_log::EventDetails::Ui(ui::Event::MaxBidSet(item_bid)) => Self::handle_event_with(
-                transaction,
-                &self.bidding_state_store,
-                &self.even_writer,
-                item_bid.item.clone(),
-                |old_state| Self::handle_max_bid_event(item_bid.item, old_state, item_bid.price),
-            )?,
+            event_log::EventDetails::AuctionHouse(event) => {
+                let insertion_id = self.bidding_state_store.next_insertion_id_tr(transaction)?;
+                Self::handle_event_with(
+                    transaction,
+                    &self.bidding_state_store,
+                    &self.even_writer,
+                    event.item.clone(),
+                    |old_state| {
+                        Self::handle_auction_house_event(event.item, old_state, event.event, now, insertion_id)
+                    },
+                )?
+            }
+            event_log::EventDetails::Ui(ui::Event::MaxBidSet(item_bid)) => {
+                let insertion_id = self.bidding_state_store.next_insertion_id_tr(transaction)?;
+                Self::handle_event_with(
+                    transaction,
+                    &self.bidding_state_store,
+                    &self.even_writer,
+                    item_bid.item.clone(),
+                    |old_state| {
+                        Self::handle_max_bid_event(item_bid.item, old_state, item_bid.price, now, insertion_id)
+                    },
+                )?
+            }
+            event_log::EventDetails::Tick => {
+                for item_id in self.bidding_state_store.list_pending_tr(transaction)? {
+                    let insertion_id = self.bidding_state_store.next_insertion_id_tr(transaction)?;
+                    Self::handle_event_with(
+                        transaction,
+                        &self.bidding_state_store,
+                        &self.even_writer,
+                        item_id.clone(),
+                        |old_state| Self::handle_tick_event(item_id, old_state, now, insertion_id),
+                    )?;
+                }
+            }
             _ => (),
         })
     }
@@ -372,3 +648,93 @@ where
         BIDDING_ENGINE_SERVICE_ID.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn other(name: &str) -> Bidder {
+        Bidder::Other(name.to_owned())
+    }
+
+    fn bid_event(bidder: Bidder, price: Amount) -> auction_house::EventDetails {
+        auction_house::EventDetails::Bid(BidDetails { bidder, price })
+    }
+
+    #[test]
+    fn records_bids_that_do_not_beat_the_current_leader() {
+        let state = AuctionState::default()
+            .handle_auction_event(bid_event(other("alice"), 100))
+            .handle_auction_event(bid_event(other("bob"), 50));
+
+        assert_eq!(state.bids.get(&other("bob")), Some(&50));
+        assert_eq!(state.higest_bid().unwrap().price, 100);
+    }
+
+    #[test]
+    fn cancelling_the_leader_reveals_the_next_highest_recorded_bid() {
+        let state = AuctionState::default()
+            .handle_auction_event(bid_event(other("alice"), 100))
+            .handle_auction_event(bid_event(other("bob"), 50))
+            .handle_auction_event(auction_house::EventDetails::BidCancelled(BidDetails {
+                bidder: other("alice"),
+                price: 100,
+            }));
+
+        let leader = state.higest_bid().unwrap();
+        assert_eq!(leader.bidder, other("bob"));
+        assert_eq!(leader.price, 50);
+    }
+
+    #[test]
+    fn evicts_the_lowest_bid_once_over_capacity() {
+        let mut state = AuctionState::default();
+        for i in 0..(MAX_TRACKED_BIDS + 2) {
+            state = state.handle_auction_event(bid_event(other(&format!("bidder-{i}")), i as Amount));
+        }
+
+        assert_eq!(state.bids.len(), MAX_TRACKED_BIDS);
+        assert!(!state.bids.contains_key(&other("bidder-0")));
+        assert!(!state.bids.contains_key(&other("bidder-1")));
+    }
+
+    #[test]
+    fn get_next_bid_does_not_repeat_a_still_pending_bid() {
+        let state = AuctionState {
+            bids: [(other("alice"), 100)].into_iter().collect(),
+            pending_bid: Some(101),
+            ..Default::default()
+        };
+
+        assert_eq!(state.get_next_bid(1000, SystemTime::now(), DEFAULT_SNIPE_LEAD), None);
+    }
+
+    #[test]
+    fn get_next_bid_bids_again_once_outbid_past_the_pending_price() {
+        let state = AuctionState {
+            bids: [(other("alice"), 150)].into_iter().collect(),
+            pending_bid: Some(101),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.get_next_bid(1000, SystemTime::now(), DEFAULT_SNIPE_LEAD),
+            Some(151)
+        );
+    }
+
+    #[test]
+    fn winning_marks_the_auction_closed_so_it_stops_being_pending() {
+        let (new_state, events) = BiddingEngine::<persistence::InMemoryPersistence>::handle_auction_house_event(
+            "item-1".to_owned(),
+            Some(AuctionBiddingState::default()),
+            auction_house::EventDetails::Won,
+            SystemTime::now(),
+            0,
+        )
+        .unwrap();
+
+        assert!(new_state.unwrap().state.closed);
+        assert_eq!(events, vec![Event::ClaimRequired("item-1".to_owned())]);
+    }
+}
@@ -16,13 +16,18 @@ impl super::BiddingStateStore for PostgresBiddingStateStore {
         item_id: crate::auction::ItemIdRef,
     ) -> anyhow::Result<Option<super::AuctionBiddingState>> {
         Ok(
-            conn.query_opt("SELECT max_bid, higest_bid_bidder, higest_bid_price, highest_bid_increment, closed FROM bidding_state WHERE item_id = $0", &[&item_id])?
+            conn.query_opt("SELECT max_bid, snipe_lead_ms, closed, ends_at FROM bidding_state WHERE item_id = $0", &[&item_id])?
             .map::<Result<_>, _>(|row| {
             Ok(super::AuctionBiddingState {
                 max_bid: u64::try_from(row.get::<'_, _, i64>("max_bid"))?,
+                snipe_lead: todo!(),
                 state: super::AuctionState {
+                    // TODO: load the top bids for this item from their own
+                    // `bidding_state_bid` table, keyed by `(item_id, bidder)`.
+                    bids: todo!(),
                     closed: row.get("closed"),
-                    higest_bid: todo!(),
+                    ends_at: todo!(),
+                    pending_bid: todo!(),
                 }
             })
         }).transpose()?)
@@ -36,4 +41,20 @@ impl super::BiddingStateStore for PostgresBiddingStateStore {
     ) -> anyhow::Result<()> {
         todo!()
     }
+
+    fn list_pending_tr(
+        &self,
+        _conn: &mut persistence::postgres::PostgresTransaction,
+    ) -> anyhow::Result<Vec<crate::auction::ItemId>> {
+        todo!()
+    }
+
+    /// Unimplemented: panics via `todo!()`. No database sequence backs the
+    /// counter in this store.
+    fn next_insertion_id_tr(
+        &self,
+        _conn: &mut persistence::postgres::PostgresTransaction,
+    ) -> anyhow::Result<u64> {
+        todo!()
+    }
 }
@@ -0,0 +1,434 @@
+//! Durable outbound bid dispatch
+//!
+//! `BiddingEngine` only ever writes `Event::Bid`s into the event log --
+//! something still has to reliably get each one to the external auction
+//! house, with at-least-once delivery and crash recovery. This module is
+//! that something: a small persistent job queue, with leasing (so two
+//! dispatchers never submit the same bid twice) and a heartbeat (so a
+//! dispatcher that died mid-submission doesn't hold its claim forever).
+
+mod in_memory;
+mod postgres;
+
+pub use self::in_memory::*;
+
+use crate::auction::ItemBid;
+use crate::event_log;
+use crate::persistence::{self, Connection};
+use crate::service::bidding_engine::{PrioritizedBid, SharedBiddingStateStore};
+use crate::service::{JoinHandle, ServiceControl};
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub type JobId = u64;
+
+/// How long a claimed job may go without a heartbeat before the reaper
+/// assumes its dispatcher died and puts it back in the queue
+pub const DEFAULT_LEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far past a bid's `ends_at` the dispatcher still attempts
+/// submission
+///
+/// Beyond this, a claimed bid is assumed stale -- the auction house will
+/// reject it anyway -- so it is dropped instead, freeing the dispatcher
+/// to move on to whatever is next in priority order.
+pub const DEFAULT_STALE_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How often a job's heartbeat is refreshed while `submit_bid` is in
+/// flight
+///
+/// Kept well under `DEFAULT_LEASE_TIMEOUT` so a submission that's merely
+/// slow -- rather than its dispatcher being dead -- never loses its lease
+/// to the `Reaper` mid-flight, which would otherwise let the bid be
+/// claimed and submitted a second time.
+pub const DEFAULT_IN_FLIGHT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How finely the in-flight heartbeat thread polls for `stop`
+///
+/// Sleeping for the full `in_flight_heartbeat_interval` in one go would
+/// make `submit_with_heartbeat` block for up to that long after every
+/// submission, no matter how fast it actually completed -- exactly the
+/// priority ordering this dispatcher exists to respect would be defeated
+/// by its own heartbeat. Polling in small slices keeps the join near-
+/// instant once submission finishes.
+const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    New,
+    Running,
+    Done,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    pub id: JobId,
+    pub queue: String,
+    pub payload: PrioritizedBid,
+    pub status: JobStatus,
+    pub heartbeat: SystemTime,
+    pub next_attempt_at: SystemTime,
+}
+
+impl Job {
+    /// Whether this job's auction has already closed (or is about to) by
+    /// more than `grace_period`, i.e. submitting it now would be pointless
+    fn is_stale(&self, now: SystemTime, grace_period: Duration) -> bool {
+        self.payload
+            .ends_at
+            .map(|ends_at| now > ends_at + grace_period)
+            .unwrap_or(false)
+    }
+}
+
+/// A persistent queue of bids waiting to be submitted to the auction
+/// house
+///
+/// Paralleling `bidding_engine::BiddingStateStore`, there is a Postgres
+/// implementation and an in-memory one; the former is what gives us
+/// crash recovery, the latter is a drop-in for tests and local dev.
+pub trait DispatchQueue {
+    type Persistence: persistence::Persistence;
+
+    fn enqueue_tr<'a>(
+        &self,
+        conn: &mut <<<Self as DispatchQueue>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+        queue: &str,
+        payload: PrioritizedBid,
+    ) -> Result<()>;
+
+    /// Atomically claims the highest-priority eligible job in `queue`:
+    /// one that is `new`, or `running` with a `heartbeat` older than
+    /// `lease_timeout`
+    ///
+    /// Eligible jobs are ordered primarily by their payload's `ends_at`
+    /// (soonest-closing first, with jobs whose `ends_at` is unknown
+    /// sorted last), and ties -- including between jobs with no known
+    /// `ends_at` at all -- are broken by `insertion_id`, so an auction
+    /// closing in 5 seconds never sits behind one closing in an hour.
+    ///
+    /// On Postgres this is a single
+    /// `UPDATE ... SET status = 'running', heartbeat = now() WHERE id =
+    /// (SELECT ... FOR UPDATE SKIP LOCKED LIMIT 1) RETURNING *`, so two
+    /// dispatchers racing for work never claim the same row.
+    fn claim_tr<'a>(
+        &self,
+        conn: &mut <<<Self as DispatchQueue>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+        queue: &str,
+        lease_timeout: Duration,
+    ) -> Result<Option<Job>>;
+
+    /// Refreshes `heartbeat` on a claimed job, proving its dispatcher is
+    /// still alive and submitting
+    fn heartbeat_tr<'a>(
+        &self,
+        conn: &mut <<<Self as DispatchQueue>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+        job_id: JobId,
+    ) -> Result<()>;
+
+    /// Marks a job `done` once it has been successfully submitted
+    fn complete_tr<'a>(
+        &self,
+        conn: &mut <<<Self as DispatchQueue>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+        job_id: JobId,
+    ) -> Result<()>;
+
+    /// Reschedules a job for another attempt after a failed submission,
+    /// putting it back to `new` with `next_attempt_at` set to `backoff`
+    /// from now
+    fn retry_tr<'a>(
+        &self,
+        conn: &mut <<<Self as DispatchQueue>::Persistence as persistence::Persistence>::Connection as persistence::Connection>::Transaction<'a>,
+        job_id: JobId,
+        backoff: Duration,
+    ) -> Result<()>;
+}
+
+pub type SharedDispatchQueue<P> = Arc<dyn DispatchQueue<Persistence = P> + Send + Sync>;
+
+/// Submits a bid to the external auction house
+///
+/// Kept as a trait so the dispatcher can be exercised against a fake in
+/// tests without talking to a real auction house.
+pub trait AuctionHouseClient {
+    fn submit_bid(&self, bid: &ItemBid) -> Result<()>;
+}
+
+pub type SharedAuctionHouseClient = Arc<dyn AuctionHouseClient + Send + Sync>;
+
+pub const BID_DISPATCH_QUEUE: &str = "bid-dispatch";
+
+/// Feeds a `bidding_engine::Event` straight off the log into the dispatch
+/// queue, if it is actually a bid that needs delivering
+///
+/// This is what closes the loop: `BiddingEngine` only ever decides to bid
+/// and writes that decision down; this (called from the `f` of a
+/// `ServiceControl::spawn_event_loop` follower on the same log) is what
+/// turns that decision into a durable, at-least-once delivery attempt.
+pub fn enqueue_from_bidding_engine_event<'a, P>(
+    queue: &SharedDispatchQueue<P>,
+    conn: &mut <<P as persistence::Persistence>::Connection as Connection>::Transaction<'a>,
+    event: crate::service::bidding_engine::Event,
+) -> Result<()>
+where
+    P: persistence::Persistence,
+{
+    if let crate::service::bidding_engine::Event::Bid(prioritized_bid) = event {
+        queue.enqueue_tr(conn, BID_DISPATCH_QUEUE, prioritized_bid)?;
+    }
+    Ok(())
+}
+
+/// Claims jobs off a `DispatchQueue` and submits them, retrying on
+/// failure with backoff
+pub struct Dispatcher<P>
+where
+    P: persistence::Persistence,
+{
+    connection: Arc<Mutex<P::Connection>>,
+    queue: SharedDispatchQueue<P>,
+    bidding_state_store: SharedBiddingStateStore<P>,
+    auction_house: SharedAuctionHouseClient,
+    event_writer: event_log::SharedWriter<P>,
+    lease_timeout: Duration,
+    stale_grace_period: Duration,
+    in_flight_heartbeat_interval: Duration,
+}
+
+impl<P> Dispatcher<P>
+where
+    P: persistence::Persistence,
+{
+    pub fn new(
+        connection: P::Connection,
+        queue: SharedDispatchQueue<P>,
+        bidding_state_store: SharedBiddingStateStore<P>,
+        auction_house: SharedAuctionHouseClient,
+        event_writer: event_log::SharedWriter<P>,
+        lease_timeout: Duration,
+        stale_grace_period: Duration,
+    ) -> Self {
+        Self {
+            connection: Arc::new(Mutex::new(connection)),
+            queue,
+            bidding_state_store,
+            auction_house,
+            event_writer,
+            lease_timeout,
+            stale_grace_period,
+            in_flight_heartbeat_interval: DEFAULT_IN_FLIGHT_HEARTBEAT_INTERVAL,
+        }
+    }
+}
+
+impl<P> Dispatcher<P>
+where
+    P: persistence::Persistence + Send + Sync + 'static,
+    P::Connection: Send + Sync + 'static,
+{
+    /// Spawns the dispatch loop: claim the highest-priority job, submit
+    /// it, mark it done; on failure, back off and retry rather than crash
+    /// the service
+    ///
+    /// A claimed job whose auction has already closed (or is about to, per
+    /// `stale_grace_period`) is never submitted -- it is dropped and a
+    /// `bidding_engine::Event::BidEvicted` is written instead, so the
+    /// eviction is still auditable off the log. "Already closed" is
+    /// re-checked against the bidding engine's own `AuctionBiddingState`
+    /// at claim time, not just inferred from `ends_at`: a `Closed` or
+    /// `Won` event can land after the bid was queued but before the
+    /// dispatcher got to it, and `PrioritizedBid` has no way to know that
+    /// on its own.
+    pub fn spawn(self, control: &ServiceControl) -> JoinHandle {
+        control.spawn_loop(move || {
+            let mut connection = self.connection.lock().expect("lock");
+            let mut transaction = connection.start_transaction()?;
+            let job = match self.queue.claim_tr(&mut transaction, BID_DISPATCH_QUEUE, self.lease_timeout)? {
+                Some(job) => job,
+                None => {
+                    transaction.commit()?;
+                    std::thread::sleep(Duration::from_millis(100));
+                    return Ok(());
+                }
+            };
+
+            let auction_closed = self
+                .bidding_state_store
+                .load_tr(&mut transaction, &job.payload.bid.item)?
+                .map(|state| state.state.closed)
+                .unwrap_or(false);
+
+            if auction_closed || job.is_stale(SystemTime::now(), self.stale_grace_period) {
+                self.queue.complete_tr(&mut transaction, job.id)?;
+                self.event_writer.write_tr(
+                    &mut transaction,
+                    &[event_log::EventDetails::BiddingEngine(
+                        crate::service::bidding_engine::Event::BidEvicted(job.payload.bid.item),
+                    )],
+                )?;
+                return transaction.commit();
+            }
+            transaction.commit()?;
+            // Dropped before submission so the heartbeat thread below can
+            // take the lock itself -- holding it here would deadlock.
+            drop(connection);
+
+            let result = self.submit_with_heartbeat(job.id, &job.payload.bid);
+
+            let mut connection = self.connection.lock().expect("lock");
+            let mut transaction = connection.start_transaction()?;
+            match result {
+                Ok(()) => self.queue.complete_tr(&mut transaction, job.id)?,
+                Err(_) => self.queue.retry_tr(&mut transaction, job.id, Duration::from_secs(1))?,
+            }
+            transaction.commit()
+        })
+    }
+
+    /// Submits `bid` to the auction house, refreshing `job_id`'s
+    /// heartbeat on `in_flight_heartbeat_interval` for as long as the
+    /// submission is running
+    ///
+    /// `submit_bid` is a single blocking call with no progress callback of
+    /// its own, so the only way to keep the lease alive while a slow (but
+    /// not dead) submission is in flight is a second thread ticking the
+    /// heartbeat alongside it.
+    fn submit_with_heartbeat(&self, job_id: JobId, bid: &ItemBid) -> Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let heartbeat = std::thread::spawn({
+            let stop = stop.clone();
+            let connection = self.connection.clone();
+            let queue = self.queue.clone();
+            let interval = self.in_flight_heartbeat_interval;
+            move || {
+                let mut since_last_heartbeat = Duration::ZERO;
+                while !stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(HEARTBEAT_POLL_INTERVAL);
+                    since_last_heartbeat += HEARTBEAT_POLL_INTERVAL;
+
+                    if stop.load(Ordering::Relaxed) || since_last_heartbeat < interval {
+                        continue;
+                    }
+                    since_last_heartbeat = Duration::ZERO;
+
+                    let mut connection = connection.lock().expect("lock");
+                    let mut transaction = match connection.start_transaction() {
+                        Ok(transaction) => transaction,
+                        Err(_) => continue,
+                    };
+                    if queue.heartbeat_tr(&mut transaction, job_id).is_ok() {
+                        let _ = transaction.commit();
+                    }
+                }
+            }
+        });
+
+        let result = self.auction_house.submit_bid(bid);
+
+        stop.store(true, Ordering::Relaxed);
+        heartbeat.join().expect("heartbeat thread panicked");
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::bidding_engine::PrioritizedBid;
+
+    fn job(ends_at: Option<SystemTime>) -> Job {
+        Job {
+            id: 1,
+            queue: BID_DISPATCH_QUEUE.to_owned(),
+            payload: PrioritizedBid {
+                bid: ItemBid {
+                    item: "item-1".to_owned(),
+                    price: 0,
+                },
+                ends_at,
+                insertion_id: 0,
+            },
+            status: JobStatus::New,
+            heartbeat: SystemTime::now(),
+            next_attempt_at: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn a_job_with_no_known_ends_at_is_never_stale() {
+        assert!(!job(None).is_stale(SystemTime::now(), Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn stale_once_the_grace_period_has_elapsed_past_ends_at() {
+        let ends_at = SystemTime::now() - Duration::from_secs(10);
+        assert!(job(Some(ends_at)).is_stale(SystemTime::now(), Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn not_yet_stale_within_the_grace_period() {
+        let ends_at = SystemTime::now() - Duration::from_millis(500);
+        assert!(!job(Some(ends_at)).is_stale(SystemTime::now(), Duration::from_secs(2)));
+    }
+}
+
+/// Once every `period`, claims at most one job through `claim_tr` and
+/// puts it straight back as `new`
+///
+/// Runs alongside the dispatcher loop. The job it claims is whichever
+/// eligible one sorts first: a `running` job whose `heartbeat` is older
+/// than `lease_timeout`, or a `new` one. Other stale `running` jobs are
+/// left for later periods, or for the dispatcher's own `claim_tr`.
+pub struct Reaper<P>
+where
+    P: persistence::Persistence,
+{
+    connection: P::Connection,
+    queue: SharedDispatchQueue<P>,
+    lease_timeout: Duration,
+    period: Duration,
+}
+
+impl<P> Reaper<P>
+where
+    P: persistence::Persistence,
+{
+    pub fn new(
+        connection: P::Connection,
+        queue: SharedDispatchQueue<P>,
+        lease_timeout: Duration,
+        period: Duration,
+    ) -> Self {
+        Self {
+            connection,
+            queue,
+            lease_timeout,
+            period,
+        }
+    }
+}
+
+impl<P> Reaper<P>
+where
+    P: persistence::Persistence + Send + Sync + 'static,
+    P::Connection: Send + Sync + 'static,
+{
+    pub fn spawn(mut self, control: &ServiceControl) -> JoinHandle {
+        control.spawn_loop(move || {
+            std::thread::sleep(self.period);
+            // Claiming a stale `running` job is exactly reclaiming it, so
+            // the reaper and the dispatcher share the same `claim_tr` --
+            // the reaper just puts what it finds straight back with
+            // `retry_tr` instead of submitting it itself, in case the
+            // original dispatcher is merely slow rather than dead.
+            let mut transaction = self.connection.start_transaction()?;
+            if let Some(job) = self.queue.claim_tr(&mut transaction, BID_DISPATCH_QUEUE, self.lease_timeout)? {
+                self.queue.retry_tr(&mut transaction, job.id, Duration::ZERO)?;
+            }
+            transaction.commit()
+        })
+    }
+}
@@ -0,0 +1,170 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub struct InMemoryDispatchQueue {
+    next_id: Mutex<JobId>,
+    jobs: Mutex<BTreeMap<JobId, Job>>,
+}
+
+impl InMemoryDispatchQueue {
+    pub fn new() -> Self {
+        Self {
+            next_id: Mutex::new(0),
+            jobs: Mutex::new(BTreeMap::default()),
+        }
+    }
+
+    pub fn new_shared() -> SharedDispatchQueue<persistence::InMemoryPersistence> {
+        Arc::new(Self::new())
+    }
+}
+
+impl DispatchQueue for InMemoryDispatchQueue {
+    type Persistence = persistence::InMemoryPersistence;
+
+    fn enqueue_tr<'a>(
+        &self,
+        _conn: &mut persistence::InMemoryTransaction,
+        queue: &str,
+        payload: PrioritizedBid,
+    ) -> Result<()> {
+        let mut next_id = self.next_id.lock().expect("lock");
+        let id = *next_id;
+        *next_id += 1;
+
+        let now = SystemTime::now();
+        self.jobs.lock().expect("lock").insert(
+            id,
+            Job {
+                id,
+                queue: queue.to_owned(),
+                payload,
+                status: JobStatus::New,
+                heartbeat: now,
+                next_attempt_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    fn claim_tr<'a>(
+        &self,
+        _conn: &mut persistence::InMemoryTransaction,
+        queue: &str,
+        lease_timeout: Duration,
+    ) -> Result<Option<Job>> {
+        let now = SystemTime::now();
+        let mut jobs = self.jobs.lock().expect("lock");
+
+        let claimable_id = jobs
+            .values()
+            .filter(|job| job.queue == queue && job.next_attempt_at <= now)
+            .filter(|job| {
+                job.status == JobStatus::New
+                    || (job.status == JobStatus::Running
+                        && now.duration_since(job.heartbeat).unwrap_or_default() >= lease_timeout)
+            })
+            // Soonest-closing first; unknown `ends_at` sorts last; ties
+            // (including between two unknown `ends_at`s) broken by
+            // `insertion_id`, so equally urgent bids submit in the order
+            // we decided to place them.
+            .min_by_key(|job| (job.payload.ends_at.is_none(), job.payload.ends_at, job.payload.insertion_id))
+            .map(|job| job.id);
+
+        Ok(match claimable_id {
+            Some(id) => {
+                let job = jobs.get_mut(&id).expect("just found it");
+                job.status = JobStatus::Running;
+                job.heartbeat = now;
+                Some(job.clone())
+            }
+            None => None,
+        })
+    }
+
+    fn heartbeat_tr<'a>(&self, _conn: &mut persistence::InMemoryTransaction, job_id: JobId) -> Result<()> {
+        if let Some(job) = self.jobs.lock().expect("lock").get_mut(&job_id) {
+            job.heartbeat = SystemTime::now();
+        }
+        Ok(())
+    }
+
+    fn complete_tr<'a>(&self, _conn: &mut persistence::InMemoryTransaction, job_id: JobId) -> Result<()> {
+        if let Some(job) = self.jobs.lock().expect("lock").get_mut(&job_id) {
+            job.status = JobStatus::Done;
+        }
+        Ok(())
+    }
+
+    fn retry_tr<'a>(
+        &self,
+        _conn: &mut persistence::InMemoryTransaction,
+        job_id: JobId,
+        backoff: Duration,
+    ) -> Result<()> {
+        if let Some(job) = self.jobs.lock().expect("lock").get_mut(&job_id) {
+            job.status = JobStatus::New;
+            job.next_attempt_at = SystemTime::now() + backoff;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auction::ItemBid;
+
+    fn enqueue(queue: &InMemoryDispatchQueue, item: &str, ends_at: Option<SystemTime>, insertion_id: u64) {
+        queue
+            .enqueue_tr(
+                &mut persistence::InMemoryTransaction,
+                "q",
+                PrioritizedBid {
+                    bid: ItemBid {
+                        item: item.to_owned(),
+                        price: 0,
+                    },
+                    ends_at,
+                    insertion_id,
+                },
+            )
+            .unwrap();
+    }
+
+    fn claim(queue: &InMemoryDispatchQueue) -> Job {
+        queue
+            .claim_tr(&mut persistence::InMemoryTransaction, "q", Duration::from_secs(30))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn claims_the_soonest_closing_job_first() {
+        let queue = InMemoryDispatchQueue::new();
+        let now = SystemTime::now();
+        enqueue(&queue, "far", Some(now + Duration::from_secs(3600)), 0);
+        enqueue(&queue, "near", Some(now + Duration::from_secs(5)), 1);
+
+        assert_eq!(claim(&queue).payload.bid.item, "near");
+    }
+
+    #[test]
+    fn jobs_with_unknown_ends_at_sort_after_known_ones() {
+        let queue = InMemoryDispatchQueue::new();
+        enqueue(&queue, "unknown", None, 0);
+        enqueue(&queue, "known", Some(SystemTime::now() + Duration::from_secs(10)), 1);
+
+        assert_eq!(claim(&queue).payload.bid.item, "known");
+    }
+
+    #[test]
+    fn ties_are_broken_by_insertion_order() {
+        let queue = InMemoryDispatchQueue::new();
+        enqueue(&queue, "second", None, 1);
+        enqueue(&queue, "first", None, 0);
+
+        assert_eq!(claim(&queue).payload.bid.item, "first");
+    }
+}
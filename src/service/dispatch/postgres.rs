@@ -0,0 +1,128 @@
+use super::*;
+use anyhow::{bail, Context};
+
+/// Backed by a table along the lines of:
+///
+/// ```sql
+/// CREATE TYPE job_status AS ENUM ('new', 'running', 'done');
+///
+/// CREATE TABLE dispatch_job (
+///     id               BIGSERIAL PRIMARY KEY,
+///     queue            TEXT NOT NULL,
+///     payload          JSONB NOT NULL,
+///     ends_at          TIMESTAMPTZ,
+///     insertion_id     BIGINT NOT NULL,
+///     status           job_status NOT NULL DEFAULT 'new',
+///     heartbeat        TIMESTAMPTZ NOT NULL DEFAULT now(),
+///     next_attempt_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+/// );
+/// ```
+///
+/// `ends_at` and `insertion_id` are pulled out of `payload` into their own
+/// columns so `claim_tr` can order by them directly rather than unpacking
+/// JSONB on every claim.
+pub struct PostgresDispatchQueue {
+    client: postgres::Client,
+}
+
+impl DispatchQueue for PostgresDispatchQueue {
+    type Persistence = persistence::postgres::PostgresPersistence;
+
+    fn enqueue_tr<'a>(
+        &self,
+        conn: &mut persistence::postgres::PostgresTransaction,
+        queue: &str,
+        payload: PrioritizedBid,
+    ) -> Result<()> {
+        let ends_at = payload.ends_at;
+        let insertion_id = payload.insertion_id as i64;
+        let payload = serde_json::to_value(&payload).context("serializing dispatch job payload")?;
+
+        conn.execute(
+            "INSERT INTO dispatch_job (queue, payload, ends_at, insertion_id) VALUES ($1, $2, $3, $4)",
+            &[&queue, &payload, &ends_at, &insertion_id],
+        )?;
+        Ok(())
+    }
+
+    /// Claims the highest-priority eligible job with a single
+    /// `SELECT ... FOR UPDATE SKIP LOCKED`-guarded `UPDATE`, so two
+    /// dispatchers racing for work never claim the same row.
+    ///
+    /// Eligible jobs are ordered soonest-closing first (`ends_at IS NULL`
+    /// sorts unknown deadlines last), with `insertion_id` breaking ties.
+    fn claim_tr<'a>(
+        &self,
+        conn: &mut persistence::postgres::PostgresTransaction,
+        queue: &str,
+        lease_timeout: Duration,
+    ) -> Result<Option<Job>> {
+        conn.query_opt(
+            "UPDATE dispatch_job SET status = 'running', heartbeat = now()
+             WHERE id = (
+                 SELECT id FROM dispatch_job
+                 WHERE queue = $1
+                   AND next_attempt_at <= now()
+                   AND (status = 'new' OR (status = 'running' AND heartbeat < now() - $2))
+                 ORDER BY ends_at IS NULL, ends_at, insertion_id
+                 FOR UPDATE SKIP LOCKED
+                 LIMIT 1
+             )
+             -- `status` is cast to `text` so reading it back doesn't need a
+             -- `FromSql` impl for the `job_status` enum type.
+             RETURNING id, queue, payload, status::text AS status, heartbeat, next_attempt_at",
+            &[&queue, &lease_timeout],
+        )?
+        .map(row_to_job)
+        .transpose()
+    }
+
+    fn heartbeat_tr<'a>(&self, conn: &mut persistence::postgres::PostgresTransaction, job_id: JobId) -> Result<()> {
+        conn.execute(
+            "UPDATE dispatch_job SET heartbeat = now() WHERE id = $1",
+            &[&(job_id as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn complete_tr<'a>(&self, conn: &mut persistence::postgres::PostgresTransaction, job_id: JobId) -> Result<()> {
+        conn.execute(
+            "UPDATE dispatch_job SET status = 'done' WHERE id = $1",
+            &[&(job_id as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn retry_tr<'a>(
+        &self,
+        conn: &mut persistence::postgres::PostgresTransaction,
+        job_id: JobId,
+        backoff: Duration,
+    ) -> Result<()> {
+        conn.execute(
+            "UPDATE dispatch_job SET status = 'new', next_attempt_at = now() + $2 WHERE id = $1",
+            &[&(job_id as i64), &backoff],
+        )?;
+        Ok(())
+    }
+}
+
+/// Maps a `dispatch_job` row -- with `status` selected as `text`, per
+/// `claim_tr`'s `RETURNING` clause -- back into a [`Job`]
+fn row_to_job(row: postgres::Row) -> Result<Job> {
+    let payload: serde_json::Value = row.try_get("payload")?;
+
+    Ok(Job {
+        id: row.try_get::<_, i64>("id")? as JobId,
+        queue: row.try_get("queue")?,
+        payload: serde_json::from_value(payload).context("deserializing dispatch job payload")?,
+        status: match row.try_get::<_, &str>("status")? {
+            "new" => JobStatus::New,
+            "running" => JobStatus::Running,
+            "done" => JobStatus::Done,
+            other => bail!("unknown job_status: {other}"),
+        },
+        heartbeat: row.try_get("heartbeat")?,
+        next_attempt_at: row.try_get("next_attempt_at")?,
+    })
+}
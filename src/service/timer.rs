@@ -0,0 +1,57 @@
+//! A service whose only job is to mark the passage of time
+//!
+//! Log-following services only do work when an event arrives, so the
+//! passage of time alone would never wake them. The timer service writes
+//! a `Tick` event to the log on a fixed period so every other service
+//! re-evaluates whatever it was waiting on (most importantly,
+//! `bidding_engine`'s deferred snipes).
+
+use crate::event_log;
+use crate::persistence::{self, Connection, Transaction};
+use crate::service::{JoinHandle, ServiceControl};
+use std::time::Duration;
+
+pub const TIMER_SERVICE_ID: &str = "timer";
+
+pub struct TimerService<P>
+where
+    P: persistence::Persistence,
+{
+    connection: P::Connection,
+    event_writer: event_log::SharedWriter<P>,
+    period: Duration,
+}
+
+impl<P> TimerService<P>
+where
+    P: persistence::Persistence,
+{
+    pub fn new(
+        connection: P::Connection,
+        event_writer: event_log::SharedWriter<P>,
+        period: Duration,
+    ) -> Self {
+        Self {
+            connection,
+            event_writer,
+            period,
+        }
+    }
+}
+
+impl<P> TimerService<P>
+where
+    P: persistence::Persistence + Send + Sync + 'static,
+    P::Connection: Send + Sync + 'static,
+{
+    /// Spawns a loop that writes a `Tick` event every `period`
+    pub fn spawn(mut self, control: &ServiceControl) -> JoinHandle {
+        control.spawn_loop(move || {
+            std::thread::sleep(self.period);
+            let mut transaction = self.connection.start_transaction()?;
+            self.event_writer
+                .write_tr(&mut transaction, &[event_log::EventDetails::Tick])?;
+            transaction.commit()
+        })
+    }
+}